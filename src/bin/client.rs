@@ -14,6 +14,11 @@ use bincode::{serialized_size, serialize_into, serialize, deserialize, deseriali
 enum Cmd {
     Set(String, String),
     Get(String),
+    Delete(String),
+    Batch(Vec<Cmd>),
+    Upgrade,
+    Scan(String),
+    Range(String, String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -49,6 +54,65 @@ fn set(mut socket: UnixStream, args: Vec<String>) -> Result<Response, String> {
     decoded.map_err(|e| e.description().to_string())
 }
 
+fn delete(mut socket: UnixStream, args: Vec<String>) -> Result<Response, String> {
+    let key = try!(args.get(2)
+        .ok_or("Not enough args to `delete`".to_string())
+        .map_err(|s| s.to_string()));
+    try!(serialize_into(&mut socket, &Cmd::Delete(key.clone()), Infinite)
+         .map_err(|e| e.description().to_string()));
+    let decoded: Result<Response, bincode::Error> = deserialize_from(&mut socket, Infinite);
+    decoded.map_err(|e| e.description().to_string())
+}
+
+// `batch key1 value1 key2 value2 ...` - sets every pair atomically.
+fn batch(mut socket: UnixStream, args: Vec<String>) -> Result<Response, String> {
+    let pairs = &args[2..];
+    if pairs.is_empty() || pairs.len() % 2 != 0 {
+        return Err("`batch` needs an even number of key/value args".to_string());
+    }
+    let cmds: Vec<Cmd> = pairs.chunks(2)
+        .map(|kv| Cmd::Set(kv[0].clone(), kv[1].clone()))
+        .collect();
+    try!(serialize_into(&mut socket, &Cmd::Batch(cmds), Infinite)
+         .map_err(|e| e.description().to_string()));
+    let decoded: Result<Response, bincode::Error> = deserialize_from(&mut socket, Infinite);
+    decoded.map_err(|e| e.description().to_string())
+}
+
+// `upgrade` - migrate any sstable still in an older format version to
+// the current layout and refresh the MANIFEST. Takes no args.
+fn upgrade(mut socket: UnixStream, _args: Vec<String>) -> Result<Response, String> {
+    try!(serialize_into(&mut socket, &Cmd::Upgrade, Infinite)
+         .map_err(|e| e.description().to_string()));
+    let decoded: Result<Response, bincode::Error> = deserialize_from(&mut socket, Infinite);
+    decoded.map_err(|e| e.description().to_string())
+}
+
+// `scan prefix` - all keys starting with `prefix`.
+fn scan(mut socket: UnixStream, args: Vec<String>) -> Result<Response, String> {
+    let prefix = try!(args.get(2)
+        .ok_or("Not enough args to `scan`".to_string())
+        .map_err(|s| s.to_string()));
+    try!(serialize_into(&mut socket, &Cmd::Scan(prefix.clone()), Infinite)
+         .map_err(|e| e.description().to_string()));
+    let decoded: Result<Response, bincode::Error> = deserialize_from(&mut socket, Infinite);
+    decoded.map_err(|e| e.description().to_string())
+}
+
+// `range start end` - all keys in `[start, end)`.
+fn range(mut socket: UnixStream, args: Vec<String>) -> Result<Response, String> {
+    let start = try!(args.get(2)
+        .ok_or("Not enough args to `range`".to_string())
+        .map_err(|s| s.to_string()));
+    let end = try!(args.get(3)
+        .ok_or("Not enough args to `range`".to_string())
+        .map_err(|s| s.to_string()));
+    try!(serialize_into(&mut socket, &Cmd::Range(start.clone(), end.clone()), Infinite)
+         .map_err(|e| e.description().to_string()));
+    let decoded: Result<Response, bincode::Error> = deserialize_from(&mut socket, Infinite);
+    decoded.map_err(|e| e.description().to_string())
+}
+
 fn main() {
 
     let args: Vec<String> = env::args().collect();
@@ -65,6 +129,11 @@ fn main() {
     let res = match args.get(1).map(|s| s.as_ref()) {
         Some("set") => set(socket, args),
         Some("get") => get(socket, args),
+        Some("delete") => delete(socket, args),
+        Some("batch") => batch(socket, args),
+        Some("upgrade") => upgrade(socket, args),
+        Some("scan") => scan(socket, args),
+        Some("range") => range(socket, args),
         Some(_)     => Err(usage()),
         None        => Err(usage()),
     };
@@ -19,15 +19,51 @@ extern crate fwdb;
 
 use std::os::unix::net::{UnixStream, UnixListener};
 use std::error::Error;
+use std::io;
+use std::env;
 
 use bincode::{serialize_into, deserialize_from, Infinite};
 
 use fwdb::*;
 
-fn run_cmd(cmd: database::Cmd, db: &mut database::Database) -> database::Response {
+// A batch only carries sets and deletes; reject anything else (a
+// nested `Batch`, or a read) rather than silently dropping it.
+fn batch_from_cmds(cmds: Vec<database::Cmd>) -> database::Result<database::WriteBatch<String>> {
+    let mut batch = database::WriteBatch::new();
+    for cmd in cmds {
+        match cmd {
+            database::Cmd::Set(key, value) => { batch.set(&key, value); }
+            database::Cmd::Delete(key) => { batch.delete(&key); }
+            _ => return Err(database::Error::from(io::Error::new(
+                io::ErrorKind::InvalidInput, "batch may only contain Set/Delete"))),
+        }
+    }
+    Ok(batch)
+}
+
+// Renders a `scan`/`range` result as one `key=value` pair per line.
+fn format_pairs(pairs: &[(String, String)]) -> String {
+    pairs.iter()
+        .map(|&(ref k, ref v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn run_cmd(cmd: database::Cmd, db: &mut database::Database<String>) -> database::Response {
     let res = match cmd {
-        database::Cmd::Set(key, value) => db.set(&key, &value).map(|_| format!("set {}", key)),
+        database::Cmd::Set(key, value) => db.set(&key, value).map(|_| format!("set {}", key)),
         database::Cmd::Get(key) => db.get(&key),
+        database::Cmd::Delete(key) => db.delete(&key).map(|_| format!("deleted {}", key)),
+        database::Cmd::Batch(cmds) => {
+            let n = cmds.len();
+            match batch_from_cmds(cmds) {
+                Ok(batch) => db.write(batch).map(|_| format!("applied {} ops", n)),
+                Err(e) => Err(e),
+            }
+        }
+        database::Cmd::Upgrade => db.upgrade().map(|n| format!("upgraded {} tables", n)),
+        database::Cmd::Scan(prefix) => db.scan(&prefix).map(|pairs| format_pairs(&pairs)),
+        database::Cmd::Range(start, end) => db.range(&start, &end).map(|pairs| format_pairs(&pairs)),
     };
 
     match res {
@@ -36,7 +72,7 @@ fn run_cmd(cmd: database::Cmd, db: &mut database::Database) -> database::Respons
     }
 }
 
-fn handle(mut stream: UnixStream, db: &mut database::Database) -> database::Result<()> {
+fn handle(mut stream: UnixStream, db: &mut database::Database<String>) -> database::Result<()> {
     let decoded: database::Result<database::Cmd> = deserialize_from(&mut stream, Infinite)
         .map_err(|e| database::Error::from(e));
     match decoded {
@@ -53,15 +89,28 @@ fn handle(mut stream: UnixStream, db: &mut database::Database) -> database::Resu
 
 fn main() {
 
-    // TODO: parse this config from a conf file
+    // TODO: parse the rest of this config from a conf file
+    //
+    // The encryption key is the one piece operators need to supply
+    // out-of-band rather than compile in, so it alone is loaded from a
+    // file path given via `FWDB_KEY_FILE`, if set.
+    let encryption_key = env::var("FWDB_KEY_FILE").ok()
+        .map(|path| database::DatabaseConfig::load_encryption_key(&path))
+        .transpose()
+        .unwrap();
+
     let conf = database::DatabaseConfig{
         memtable_size: 200,
         block_size: 100,
-        
+        compaction_threshold: 4,
+        use_mmap: true,
+        encryption_key: encryption_key,
+        compression: database::CompressionType::None,
+
         name: "hello",
         data_dir: "/var/db/",
     };
-    let db = &mut database::Database::new(&conf).unwrap();
+    let db = &mut database::Database::<String>::new(&conf).unwrap();
 
     let listener = UnixListener::bind("fwdb.hello.sock").unwrap();
 